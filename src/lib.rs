@@ -1,7 +1,10 @@
 #![doc = include_str!("../README.md")]
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::TryFromIntError;
 use std::ops::Index;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 impl<T> Index<T> for HTB<T>
 where
@@ -305,6 +308,476 @@ where
             None => false,
         }
     }
+
+    /// Time that needs to pass before `cnt` tokens become available at `label`
+    ///
+    /// Returns [`Duration::ZERO`] if they are already available and `None` if `cnt`
+    /// exceeds what the capacity chain feeding `label` can ever hold, even after waiting
+    /// out [`time_limit`][Self::advance_ns].
+    ///
+    /// Implemented by cloning `self` and binary-searching `time_diff` in `[0, time_limit]`:
+    /// for a fixed target, [`peek_n`][Self::peek_n] is monotonic non-decreasing in elapsed
+    /// time, so each probe either rules out the lower half or the upper half of the range.
+    pub fn time_until_n(&self, label: T, cnt: usize) -> Option<Duration> {
+        if self.peek_n(label, cnt) {
+            return Some(Duration::ZERO);
+        }
+
+        let mut upper_bound = self.clone();
+        upper_bound.advance_ns(self.time_limit);
+        if !upper_bound.peek_n(label, cnt) {
+            return None;
+        }
+
+        let mut lo = 0usize;
+        let mut hi = self.time_limit;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let mut probe = self.clone();
+            probe.advance_ns(mid);
+            if probe.peek_n(label, cnt) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Some(Duration::from_nanos(lo as u64))
+    }
+
+    /// Wait until a single token is available at `label`, then consume it
+    ///
+    /// See also [`acquire_n`][Self::acquire_n]
+    #[cfg(feature = "tokio")]
+    pub async fn acquire(&mut self, label: T) {
+        self.acquire_n(label, 1).await;
+    }
+
+    /// Wait until `cnt` tokens are available at `label`, then consume them
+    ///
+    /// Sleeps for [`time_until_n`][Self::time_until_n] on the tokio runtime, advances time
+    /// by that same computed amount (not by however long the sleep actually took), then
+    /// retries [`take_n`][Self::take_n] until it succeeds.
+    ///
+    /// # Panics
+    /// Panics if `cnt` tokens can never accumulate at `label`, see
+    /// [`time_until_n`][Self::time_until_n].
+    #[cfg(feature = "tokio")]
+    pub async fn acquire_n(&mut self, label: T, cnt: usize) {
+        loop {
+            if self.take_n(label, cnt) {
+                return;
+            }
+            let wait = self
+                .time_until_n(label, cnt)
+                .expect("requested token count exceeds bucket capacity");
+            tokio::time::sleep(wait).await;
+            self.advance(wait);
+        }
+    }
+}
+
+/// A [`HTB`] keyed by an arbitrary per-client identifier
+///
+/// Rate-limits many independent clients (for example one bucket forest per IP or API key)
+/// without requiring the caller to manage a separate [`HTB`] per client by hand. Each key's
+/// bucket forest is lazily cloned from a `template` the first time that key is seen.
+#[derive(Debug, Clone)]
+pub struct KeyedHTB<K, T> {
+    template: HTB<T>,
+    ttl: Duration,
+    buckets: HashMap<K, (HTB<T>, Instant)>,
+}
+
+impl<K, T> KeyedHTB<K, T>
+where
+    K: Eq + Hash,
+    T: Copy + Eq + PartialEq,
+    usize: From<T>,
+{
+    /// Create a new keyed limiter, cloning `template` for every key it sees
+    ///
+    /// `ttl` controls how long an idle key is kept around before [`sweep`][Self::sweep]
+    /// considers it stale.
+    pub fn new(template: HTB<T>, ttl: Duration) -> Self {
+        Self {
+            template,
+            ttl,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn bucket_mut(&mut self, key: K) -> &mut HTB<T> {
+        let template = &self.template;
+        let now = Instant::now();
+        let (htb, last_seen) = self
+            .buckets
+            .entry(key)
+            .or_insert_with(|| (template.clone(), now));
+        *last_seen = now;
+        htb
+    }
+
+    /// Check if there's at least one token available at `label` for `key`
+    ///
+    /// Lazily creates a bucket forest cloned from the template if `key` hasn't been seen
+    /// yet. See also [`peek_n`][Self::peek_n].
+    pub fn peek(&mut self, key: K, label: T) -> bool {
+        self.bucket_mut(key).peek(label)
+    }
+
+    /// Check if there's at least `cnt` tokens available at `label` for `key`
+    ///
+    /// See also [`peek`][Self::peek]
+    pub fn peek_n(&mut self, key: K, label: T, cnt: usize) -> bool {
+        self.bucket_mut(key).peek_n(label, cnt)
+    }
+
+    /// Consume a single token from `label` for `key`
+    ///
+    /// Lazily creates a bucket forest cloned from the template if `key` hasn't been seen
+    /// yet. See also [`take_n`][Self::take_n].
+    pub fn take(&mut self, key: K, label: T) -> bool {
+        self.bucket_mut(key).take(label)
+    }
+
+    /// Consume `cnt` tokens from `label` for `key`
+    ///
+    /// See also [`take`][Self::take]
+    pub fn take_n(&mut self, key: K, label: T, cnt: usize) -> bool {
+        self.bucket_mut(key).take_n(label, cnt)
+    }
+
+    /// Advance time for every key's bucket forest by a number of nanoseconds
+    ///
+    /// See also [`advance`][Self::advance]
+    pub fn advance_ns(&mut self, time_diff: usize) {
+        for (htb, _) in self.buckets.values_mut() {
+            htb.advance_ns(time_diff);
+        }
+    }
+
+    /// Advance time for every key's bucket forest by [`Duration`]
+    pub fn advance(&mut self, time_diff: Duration) {
+        self.advance_ns(time_diff.as_nanos() as usize);
+    }
+
+    /// Drop idle entries
+    ///
+    /// An entry is dropped if every one of its buckets has refilled back to capacity
+    /// (equivalent to a freshly cloned template) or if it hasn't been touched since before
+    /// `now - ttl`.
+    ///
+    /// # Performance
+    ///
+    /// O(entries), meant to run on a timer rather than per-request.
+    pub fn sweep(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.buckets.retain(|_, (htb, last_seen)| {
+            let fully_refilled = htb.state.iter().all(|bucket| bucket.value == bucket.cap);
+            !fully_refilled && now.saturating_duration_since(*last_seen) < ttl
+        });
+    }
+}
+
+/// Normalizes [`IpAddr`] keys into CIDR prefix groups before they reach a [`KeyedHTB`]
+///
+/// Masks an address down to its group so that every address in the same allocated range
+/// (e.g. a customer's IPv6 block) maps to the same [`KeyedHTB`] key, rather than each one
+/// getting its own bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpGroupKey {
+    /// Leading bits of an IPv4 address kept when grouping, the rest are masked to zero
+    pub v4_bits: u8,
+    /// Leading bits of an IPv6 address kept when grouping, the rest are masked to zero
+    pub v6_bits: u8,
+}
+
+impl Default for IpGroupKey {
+    /// IPv4 addresses are grouped individually (/32); IPv6 addresses are grouped by /64,
+    /// the smallest block commonly allocated to a single customer
+    fn default() -> Self {
+        IpGroupKey {
+            v4_bits: 32,
+            v6_bits: 64,
+        }
+    }
+}
+
+impl IpGroupKey {
+    /// Mask `addr` down to its group, keeping only the leading `v4_bits`/`v6_bits` bits
+    ///
+    /// The result is the canonical key for the whole group: every address in the same
+    /// group masks down to the same value.
+    pub fn group(&self, addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let mask = mask_for_width(32, self.v4_bits) as u32;
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = mask_for_width(128, self.v6_bits);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        }
+    }
+}
+
+/// Build a `width`-bit mask with the leading `bits` bits set to one and the rest to zero
+fn mask_for_width(width: u32, bits: u8) -> u128 {
+    let bits = u32::from(bits).min(width);
+    if bits == 0 {
+        0
+    } else {
+        (!0u128 << (width - bits)) & (u128::MAX >> (128 - width))
+    }
+}
+
+/// A single bucket's configuration for [`HtbConfig`]/[`HtbBuilder`], referencing its parent
+/// by name rather than by position
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NamedBucketCfg<N> {
+    /// Name of this bucket, used to reference it as some other bucket's parent
+    pub name: N,
+    /// Name of the parent bucket, `None` for a root
+    pub parent: Option<N>,
+    /// Allowed flow rate in number of tokens per duration
+    pub rate: (usize, Duration),
+    /// Burst capacity in tokens, see [`BucketCfg::capacity`]
+    pub capacity: usize,
+}
+
+/// A bucket tree declared in any order with parents referenced by name, for example as
+/// loaded from a config file
+///
+/// See [`HtbConfig::build`] to turn this into a [`HTB`].
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HtbConfig<N> {
+    /// Buckets in any order, each referencing its parent by name
+    pub buckets: Vec<NamedBucketCfg<N>>,
+}
+
+impl<N> HtbConfig<N>
+where
+    N: Clone + Eq + Hash,
+{
+    /// Validate this configuration and build the [`HTB`] it describes
+    ///
+    /// See [`HtbBuilder::build`] for the underlying algorithm and the meaning of the
+    /// returned name-to-index map.
+    ///
+    /// # Errors
+    /// See [`HtbBuilder::build`].
+    pub fn build(self) -> Result<(HTB<usize>, HashMap<N, usize>), Error> {
+        HtbBuilder::build(self.buckets)
+    }
+}
+
+/// Builds a [`HTB`] from buckets given in any order with parents referenced by name
+///
+/// [`HTB::new`] demands buckets pre-sorted in depth-first traversal order with `T` indices
+/// matching position, which is fragile to hand-author or load from a file. `HtbBuilder`
+/// instead accepts buckets in any order, validates that they form a single tree rooted at
+/// one parentless bucket (the only shape [`HTB::new`] supports), computes the traversal and
+/// [`HTB::new`]'s `Op` sequence itself, and assigns the `usize` indices.
+pub struct HtbBuilder;
+
+impl HtbBuilder {
+    /// Reorder `buckets` into depth-first traversal order, assign each a `usize` index and
+    /// build the resulting [`HTB`]
+    ///
+    /// Returns the constructed [`HTB<usize>`] together with a map from each bucket's `name`
+    /// to the `usize` index it was assigned, so callers can still address buckets by name.
+    ///
+    /// # Errors
+    /// Returns [`Error::NoRoot`] if no bucket has `parent: None`, or if more than one does
+    /// (`HTB` only supports a single root). Returns [`Error::InvalidStructure`] for a
+    /// duplicate name, a parent referencing an unknown name, or a cycle. Also propagates
+    /// whatever [`HTB::new`] itself returns for an invalid rate.
+    pub fn build<N>(
+        buckets: Vec<NamedBucketCfg<N>>,
+    ) -> Result<(HTB<usize>, HashMap<N, usize>), Error>
+    where
+        N: Clone + Eq + Hash,
+    {
+        let mut by_name = HashMap::with_capacity(buckets.len());
+        for (pos, b) in buckets.iter().enumerate() {
+            if by_name.insert(b.name.clone(), pos).is_some() {
+                return Err(Error::InvalidStructure);
+            }
+        }
+
+        let mut children: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut roots = Vec::new();
+        for (pos, b) in buckets.iter().enumerate() {
+            match &b.parent {
+                None => roots.push(pos),
+                Some(parent) => {
+                    let parent_pos = *by_name.get(parent).ok_or(Error::InvalidStructure)?;
+                    children.entry(parent_pos).or_default().push(pos);
+                }
+            }
+        }
+        // `HTB::new` only supports a single inflow root, not a forest of independent trees
+        let root = match roots.as_slice() {
+            [root] => *root,
+            _ => return Err(Error::NoRoot),
+        };
+
+        let mut order = Vec::with_capacity(buckets.len());
+        let mut visited = vec![false; buckets.len()];
+        visit_dfs(root, &children, &mut visited, &mut order)?;
+        if order.len() != buckets.len() {
+            // some bucket wasn't reachable from the root: a cycle among non-roots
+            return Err(Error::InvalidStructure);
+        }
+
+        let mut index = vec![0usize; buckets.len()];
+        for (new_pos, &old_pos) in order.iter().enumerate() {
+            index[old_pos] = new_pos;
+        }
+
+        let mut name_to_index = HashMap::with_capacity(buckets.len());
+        let cfgs: Vec<_> = order
+            .iter()
+            .enumerate()
+            .map(|(new_pos, &old_pos)| {
+                let b = &buckets[old_pos];
+                name_to_index.insert(b.name.clone(), new_pos);
+                BucketCfg {
+                    this: new_pos,
+                    parent: b.parent.as_ref().map(|name| index[by_name[name]]),
+                    rate: b.rate,
+                    capacity: b.capacity,
+                }
+            })
+            .collect();
+
+        let htb = HTB::new(&cfgs)?;
+        Ok((htb, name_to_index))
+    }
+}
+
+/// Depth-first walk from `pos`, appending visited positions to `order`
+///
+/// Errors if `pos` was already visited, which means the tree has a cycle.
+fn visit_dfs(
+    pos: usize,
+    children: &HashMap<usize, Vec<usize>>,
+    visited: &mut [bool],
+    order: &mut Vec<usize>,
+) -> Result<(), Error> {
+    if visited[pos] {
+        return Err(Error::InvalidStructure);
+    }
+    visited[pos] = true;
+    order.push(pos);
+    if let Some(kids) = children.get(&pos) {
+        for kid in kids.iter().copied() {
+            visit_dfs(kid, children, visited, order)?;
+        }
+    }
+    Ok(())
+}
+
+/// A [`HTB`] that ticks itself instead of requiring the caller to call
+/// [`advance`][HTB::advance] before every access
+///
+/// [`take_now`][Self::take_now]/[`peek_now`][Self::peek_now] advance the wrapped [`HTB`] by
+/// the time elapsed since the previous access, then perform the requested operation,
+/// eliminating the class of bugs where a caller forgets to tick the clock.
+///
+/// `last_updated` is stored as a 32-bit offset in nanoseconds from a `base` [`Instant`]
+/// rather than as a full `Instant`. `base` is not part of the struct: the caller supplies it
+/// to every `_now` call instead, so many clocked buckets (for example one per [`KeyedHTB`]
+/// entry) can share a single `base` and each pay only 4 bytes for a timestamp instead of 16.
+///
+/// # Wraparound
+///
+/// The offset wraps every `2^32` nanoseconds (~4.29s) past `base`. Elapsed time between two
+/// accesses is computed with wrapping subtraction, which recovers the correct delta across a
+/// single wrap as long as consecutive accesses land less than `2^32` nanoseconds (~4.29s)
+/// apart; an idle period of `2^32` nanoseconds or longer is counted modulo `2^32` and so
+/// undercounts the next advance, the same tradeoff any truncated monotonic counter makes.
+#[derive(Debug, Clone)]
+pub struct ClockedHTB<T> {
+    htb: HTB<T>,
+    last_updated: u32,
+}
+
+impl<T> ClockedHTB<T>
+where
+    T: Copy + Eq + PartialEq,
+    usize: From<T>,
+{
+    /// Wrap `htb`, starting its clock at `base`
+    ///
+    /// `base` is typically `Instant::now()`; pass that same `base` to every subsequent
+    /// `_now` call on this instance, see the type-level docs.
+    pub fn new(htb: HTB<T>, base: Instant) -> Self {
+        ClockedHTB {
+            htb,
+            last_updated: Self::offset_ns(base),
+        }
+    }
+
+    /// Current offset of [`Instant::now`] from `base`, truncated to 32 bits
+    fn offset_ns(base: Instant) -> u32 {
+        Instant::now().duration_since(base).as_nanos() as u32
+    }
+
+    fn tick_to(&mut self, now: u32) {
+        let elapsed = now.wrapping_sub(self.last_updated);
+        self.htb.advance_ns(elapsed as usize);
+        self.last_updated = now;
+    }
+
+    /// Advance to now, then check if there's at least one token available at `label`
+    ///
+    /// `base` must be the same [`Instant`] passed to [`new`][Self::new].
+    ///
+    /// See also [`peek`][HTB::peek]
+    pub fn peek_now(&mut self, base: Instant, label: T) -> bool {
+        let now = Self::offset_ns(base);
+        self.tick_to(now);
+        self.htb.peek(label)
+    }
+
+    /// Advance to now, then consume a single token from `label`
+    ///
+    /// `base` must be the same [`Instant`] passed to [`new`][Self::new].
+    ///
+    /// See also [`take`][HTB::take]
+    pub fn take_now(&mut self, base: Instant, label: T) -> bool {
+        let now = Self::offset_ns(base);
+        self.tick_to(now);
+        self.htb.take(label)
+    }
+
+    /// Advance to an explicit monotonic nanosecond timestamp, then check token availability
+    ///
+    /// For callers with their own monotonic clock instead of [`Instant::now`]; `now` is
+    /// expected to be comparable to `base` the same way [`offset_ns`][Self::offset_ns] would
+    /// compute it, and is subject to the same [wraparound](#wraparound) caveat as
+    /// [`peek_now`][Self::peek_now].
+    pub fn peek_at(&mut self, now: u32, label: T) -> bool {
+        self.tick_to(now);
+        self.htb.peek(label)
+    }
+
+    /// Advance to an explicit monotonic nanosecond timestamp, then consume a single token
+    ///
+    /// See [`peek_at`][Self::peek_at] for the meaning of `now`.
+    pub fn take_at(&mut self, now: u32, label: T) -> bool {
+        self.tick_to(now);
+        self.htb.take(label)
+    }
+
+    /// Access the wrapped [`HTB`] without advancing the clock
+    pub fn inner(&self) -> &HTB<T> {
+        &self.htb
+    }
 }
 
 #[cfg(test)]
@@ -380,4 +853,140 @@ mod tests {
         htb.advance_ns(usize::MAX);
         assert!(htb.take_n(Rate::Hedge, 4));
     }
+
+    #[test]
+    fn time_until_n_binary_searches_the_wait() {
+        let mut htb = sample_htb();
+        assert!(htb.take_n(Rate::Hedge, 10));
+        assert_eq!(htb.time_until_n(Rate::Hedge, 1), Some(Duration::from_millis(1)));
+        assert_eq!(htb.time_until_n(Rate::Hedge, 2000), None);
+
+        htb.advance(Duration::from_millis(1));
+        assert_eq!(htb.time_until_n(Rate::Hedge, 1), Some(Duration::ZERO));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn acquire_waits_for_tokens() {
+        let mut htb = sample_htb();
+        assert!(htb.take_n(Rate::Hedge, 10));
+        assert!(!htb.peek(Rate::Hedge));
+
+        htb.acquire(Rate::Hedge).await;
+        assert!(!htb.peek(Rate::Hedge));
+    }
+
+    #[test]
+    fn keyed_htb_isolates_and_sweeps() {
+        let mut keyed = KeyedHTB::new(sample_htb(), Duration::from_secs(60));
+        assert!(keyed.take_n("alice", Rate::Hedge, 10));
+        assert!(!keyed.peek("alice", Rate::Hedge));
+        // a different key gets its own, fully-stocked forest
+        assert!(keyed.take_n("bob", Rate::Hedge, 10));
+
+        let now = Instant::now();
+        keyed.sweep(now);
+        assert!(!keyed.peek("alice", Rate::Hedge));
+
+        keyed.advance(Duration::from_secs(1));
+        keyed.sweep(Instant::now());
+        assert!(keyed.peek("alice", Rate::Hedge));
+    }
+
+    #[test]
+    fn ip_group_key_collapses_v6_range_but_not_v4() {
+        let groups = IpGroupKey::default();
+
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::ffff:ffff:ffff:ffff".parse().unwrap();
+        assert_eq!(groups.group(a), groups.group(b));
+
+        let c: IpAddr = "2001:db8:1::1".parse().unwrap();
+        assert_ne!(groups.group(a), groups.group(c));
+
+        let v4_a: IpAddr = "192.0.2.1".parse().unwrap();
+        let v4_b: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_ne!(groups.group(v4_a), groups.group(v4_b));
+    }
+
+    #[test]
+    fn htb_builder_accepts_buckets_in_any_order() {
+        let (mut htb, index) = HtbConfig {
+            buckets: vec![
+                NamedBucketCfg {
+                    name: "hedge",
+                    parent: Some("short"),
+                    rate: (1000, Duration::from_secs(1)),
+                    capacity: 10,
+                },
+                NamedBucketCfg {
+                    name: "long",
+                    parent: None,
+                    rate: (100, Duration::from_millis(200)),
+                    capacity: 1500,
+                },
+                NamedBucketCfg {
+                    name: "short",
+                    parent: Some("long"),
+                    rate: (250, Duration::from_secs(1)),
+                    capacity: 250,
+                },
+            ],
+        }
+        .build()
+        .unwrap();
+
+        assert!(htb.take_n(index["hedge"], 10));
+        assert!(!htb.take(index["hedge"]));
+
+        let bad = HtbConfig {
+            buckets: vec![NamedBucketCfg {
+                name: "orphan",
+                parent: Some("missing"),
+                rate: (1, Duration::from_secs(1)),
+                capacity: 1,
+            }],
+        }
+        .build();
+        assert!(matches!(bad, Err(Error::InvalidStructure)));
+
+        let two_roots = HtbConfig {
+            buckets: vec![
+                NamedBucketCfg {
+                    name: "a",
+                    parent: None,
+                    rate: (1, Duration::from_secs(1)),
+                    capacity: 1,
+                },
+                NamedBucketCfg {
+                    name: "b",
+                    parent: None,
+                    rate: (1, Duration::from_secs(1)),
+                    capacity: 1,
+                },
+            ],
+        }
+        .build();
+        assert!(matches!(two_roots, Err(Error::NoRoot)));
+    }
+
+    #[test]
+    fn clocked_htb_advances_on_access() {
+        let mut clocked = ClockedHTB::new(sample_htb(), Instant::now());
+        assert!(clocked.take_at(0, Rate::Hedge));
+        for _ in 0..9 {
+            assert!(clocked.take_at(0, Rate::Hedge));
+        }
+        assert!(!clocked.take_at(0, Rate::Hedge));
+
+        // a millisecond later a single token has trickled back in
+        assert!(clocked.take_at(1_000_000, Rate::Hedge));
+        assert!(!clocked.take_at(1_000_000, Rate::Hedge));
+
+        // same check straddling the point where the 32-bit offset wraps around
+        let near_wrap = u32::MAX - 500_000;
+        while clocked.take_at(near_wrap, Rate::Hedge) {}
+        assert!(!clocked.peek_at(near_wrap.wrapping_add(999_999), Rate::Hedge));
+        assert!(clocked.peek_at(near_wrap.wrapping_add(1_000_000), Rate::Hedge));
+    }
 }